@@ -0,0 +1,38 @@
+use rusqlite::{Connection, DatabaseName};
+
+/// Reads up to `length` bytes starting at `offset` out of a BLOB cell,
+/// using SQLite's incremental BLOB API so the rest of the cell is never
+/// loaded into memory. `length` is clamped to what's actually left in the
+/// cell past `offset` before allocating, so a caller can't make us
+/// allocate an arbitrarily large buffer for a small BLOB.
+pub fn read_blob(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    row_id: i64,
+    offset: usize,
+    length: usize,
+) -> rusqlite::Result<Vec<u8>> {
+    let blob = conn.blob_open(DatabaseName::Main, table, column, row_id, true)?;
+    let to_read = length.min(blob.len().saturating_sub(offset));
+    let mut buf = vec![0u8; to_read];
+    let bytes_read = blob.read_at(&mut buf, offset)?;
+    buf.truncate(bytes_read);
+    Ok(buf)
+}
+
+/// Writes `bytes` into a BLOB cell starting at `offset`. The cell must
+/// already be at least `offset + bytes.len()` long: the incremental BLOB
+/// API can only overwrite existing bytes, not grow a cell, so growing one
+/// first (e.g. via an `UPDATE ... = zeroblob(?)`) is left to the caller.
+pub fn write_blob(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    row_id: i64,
+    offset: usize,
+    bytes: &[u8],
+) -> rusqlite::Result<()> {
+    let mut blob = conn.blob_open(DatabaseName::Main, table, column, row_id, false)?;
+    blob.write_at(bytes, offset)
+}
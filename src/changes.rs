@@ -0,0 +1,66 @@
+use rusqlite::hooks::Action;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// A single data-change notification, delivered only after the write that
+/// produced it has actually committed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+    action: &'static str,
+    table: String,
+    row_id: i64,
+}
+
+impl ChangeEvent {
+    fn new(action: Action, table: &str, row_id: i64) -> Self {
+        let action = match action {
+            Action::SQLITE_INSERT => "insert",
+            Action::SQLITE_UPDATE => "update",
+            Action::SQLITE_DELETE => "delete",
+            _ => "unknown",
+        };
+        Self {
+            action,
+            table: table.to_owned(),
+            row_id,
+        }
+    }
+}
+
+/// Registers `update`/`commit`/`rollback` hooks on `conn` that buffer data-change
+/// notifications as they happen and only publish them to `sender` once the
+/// enclosing transaction actually commits; a rollback discards the buffer
+/// instead. This keeps subscribers from ever seeing a change that later got
+/// rolled back.
+pub fn watch_changes(conn: &Connection, sender: broadcast::Sender<ChangeEvent>) {
+    let pending: Arc<Mutex<Vec<ChangeEvent>>> = Arc::new(Mutex::new(Vec::new()));
+
+    let update_pending = pending.clone();
+    conn.update_hook(Some(move |action, _db: &str, table: &str, row_id| {
+        update_pending
+            .lock()
+            .expect("pending change buffer lock poisoned")
+            .push(ChangeEvent::new(action, table, row_id));
+    }));
+
+    let commit_pending = pending.clone();
+    conn.commit_hook(Some(move || {
+        let mut pending = commit_pending
+            .lock()
+            .expect("pending change buffer lock poisoned");
+        for event in pending.drain(..) {
+            // No subscribers currently connected is not an error.
+            let _ = sender.send(event);
+        }
+        false
+    }));
+
+    conn.rollback_hook(Some(move || {
+        pending
+            .lock()
+            .expect("pending change buffer lock poisoned")
+            .clear();
+    }));
+}
@@ -1,15 +1,33 @@
-use chrono::Local;
+mod backup;
+mod blob;
+mod changes;
+mod pool;
+mod session;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use changes::{watch_changes, ChangeEvent};
+use chrono::{DateTime, Local};
 use clap::Parser;
+use pool::{open_configured_connection, ConnectionConfig, ReaderPool};
 use rusqlite::{
     params_from_iter,
-    types::{FromSql, FromSqlResult, ValueRef},
-    Connection,
+    types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef},
+    Connection, Statement,
 };
+use session::OnConflict;
 use serde::{Deserialize, Serialize};
 use serde_json::{Number, Value};
+use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
 use warp::Filter;
 
+/// Number of not-yet-delivered change events a slow SSE subscriber can fall
+/// behind by before the oldest ones are dropped for it.
+const CHANGE_STREAM_CAPACITY: usize = 1024;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -32,6 +50,47 @@ struct Args {
     /// Load an extension from the provided path. This flag can be used multiple times in one invocation to load multiple extensions
     #[arg(long)]
     load_extension: Option<Vec<String>>,
+    /// Number of pooled read-only connections kept open alongside the single writer connection.
+    /// Read-only statements are spread across these so they can run in parallel with each other
+    /// and with the writer under WAL mode.
+    #[arg(long, default_value_t = 4)]
+    max_readers: usize,
+    /// Row shape to use when a request doesn't specify its own "format": "rows" for positional
+    /// arrays (the default) or "objects" for column-named JSON objects.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Rows)]
+    default_format: OutputFormat,
+    /// Passphrase for opening a SQLCipher-encrypted database file. Can also be set via the
+    /// SQLITE_HTTP_KEY environment variable instead, to avoid leaking it in process listings.
+    #[arg(long, env = "SQLITE_HTTP_KEY")]
+    key: Option<String>,
+    /// SQLCipher `cipher_compatibility` PRAGMA value (1-4), for opening a database encrypted by
+    /// an older SQLCipher major version.
+    #[arg(long)]
+    cipher_compatibility: Option<u32>,
+    /// Expose a GET /changes Server-Sent Events endpoint that streams data-change
+    /// notifications (insert/update/delete, table, rowid) as they commit.
+    #[arg(long)]
+    enable_change_stream: bool,
+    /// Number of prepared statements each connection keeps cached, keyed by SQL text.
+    /// Raise this for workloads that fire the same parameterized query repeatedly.
+    #[arg(long, default_value_t = 128)]
+    stmt_cache_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Rows,
+    Objects,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Rows => write!(f, "rows"),
+            OutputFormat::Objects => write!(f, "objects"),
+        }
+    }
 }
 
 pub enum InteropValue {
@@ -58,7 +117,7 @@ impl From<InteropValue> for Value {
     fn from(value: InteropValue) -> Self {
         match value {
             InteropValue::Null => Value::Null,
-            InteropValue::Blob(v) => Value::String(format!("{:x?}", &v)),
+            InteropValue::Blob(v) => Value::String(STANDARD.encode(&v)),
             InteropValue::Integer(i) => Value::Number(Number::from(i)),
             InteropValue::Real(f) => Value::Number(Number::from_f64(f).unwrap()),
             InteropValue::Text(s) => {
@@ -68,6 +127,39 @@ impl From<InteropValue> for Value {
     }
 }
 
+/// Binds a single request argument to SQLite, recognizing `{"blob": "<base64>"}`
+/// as a BLOB parameter so round-tripping `InteropValue::Blob`'s base64 output
+/// back in as a bind arg works. Anything else is handed to `Value`'s own
+/// `ToSql` impl unchanged.
+enum BindArg<'a> {
+    Json(&'a Value),
+    Blob(Vec<u8>),
+}
+
+impl ToSql for BindArg<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            BindArg::Json(v) => v.to_sql(),
+            BindArg::Blob(bytes) => bytes.to_sql(),
+        }
+    }
+}
+
+fn bind_args(args: &[Value]) -> Vec<BindArg<'_>> {
+    args.iter()
+        .map(|v| match v.as_object() {
+            Some(map) if map.len() == 1 => match map.get("blob").and_then(Value::as_str) {
+                Some(b64) => match STANDARD.decode(b64) {
+                    Ok(bytes) => BindArg::Blob(bytes),
+                    Err(_) => BindArg::Json(v),
+                },
+                None => BindArg::Json(v),
+            },
+            _ => BindArg::Json(v),
+        })
+        .collect()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 enum SqlInput {
@@ -79,236 +171,694 @@ enum SqlInput {
 struct Input {
     sql: SqlInput,
     args: Vec<Value>,
+    /// When true, wraps a write in a session and returns the resulting
+    /// changeset (base64) in the response, for downstream replication.
+    #[serde(default)]
+    track_changes: bool,
+    /// Row shape for this request's results. Falls back to the server's
+    /// `--default-format` when omitted. Only affects single statements.
+    #[serde(default)]
+    format: Option<OutputFormat>,
+}
+
+/// A single statement's result rows, shaped according to the requested
+/// `OutputFormat`: positional arrays, or column-named objects.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Rows {
+    Positional(Vec<Vec<Value>>),
+    Named(Vec<serde_json::Map<String, Value>>),
+}
+
+impl Default for Rows {
+    fn default() -> Self {
+        Rows::Positional(Vec::new())
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct Output {
-    rows: Vec<Vec<Value>>,
+    rows: Rows,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changeset: Option<String>,
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+#[derive(Deserialize)]
+struct ApplyChangesetInput {
+    /// Base64-encoded changeset, as produced by a `track_changes` request.
+    changeset: String,
+    #[serde(default)]
+    on_conflict: OnConflict,
+}
 
-    let args = Args::parse();
-    log::debug!("Parsed CLI flags: {:?}", &args);
+#[derive(Serialize, Default)]
+struct ApplyChangesetOutput {
+    applied: bool,
+}
 
-    let Args {
-        db_path,
-        host,
-        collect_metadata,
-        disable_wal_mode,
-        disable_foreign_keys,
-        load_extension,
-    } = args;
+#[derive(Deserialize)]
+struct BackupInput {
+    /// Path to write the backup to.
+    destination: String,
+}
 
-    let db_conn = Connection::open(db_path);
-    if let Err(e) = db_conn {
-        log::error!("Couldn't open DB connection: {}", e);
-        std::process::exit(1);
-    }
-    let db_conn = db_conn.unwrap();
+#[derive(Serialize, Default)]
+struct BackupOutput {
+    total_pages: i32,
+    elapsed_ms: u128,
+}
 
-    log::info!("Setting encoding to UTF-8");
-    if let Err(e) = db_conn.execute_batch("PRAGMA encoding = \"UTF-8\"") {
-        log::error!("Couldn't set encoding to UTF-8: {}", e);
-        std::process::exit(1);
-    }
+#[derive(Deserialize)]
+struct ReadBlobInput {
+    table: String,
+    column: String,
+    row_id: i64,
+    #[serde(default)]
+    offset: usize,
+    length: usize,
+}
 
-    if !disable_wal_mode {
-        log::info!("Enabling WAL mode");
-        if let Err(e) = db_conn.execute_batch("PRAGMA journal_mode=WAL") {
-            log::error!("Couldn't enable WAL mode: {}", e);
-            std::process::exit(1);
+#[derive(Serialize, Default)]
+struct ReadBlobOutput {
+    /// Base64-encoded bytes read from the BLOB.
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct WriteBlobInput {
+    table: String,
+    column: String,
+    row_id: i64,
+    #[serde(default)]
+    offset: usize,
+    /// Base64-encoded bytes to write into the BLOB.
+    data: String,
+}
+
+#[derive(Serialize, Default)]
+struct WriteBlobOutput {
+    bytes_written: usize,
+}
+
+/// Reads every column of `row` into a positional `Vec<Value>`, skipping (and
+/// logging) any column that fails to convert.
+fn row_values(row: &rusqlite::Row, num_columns: usize) -> Vec<Value> {
+    let mut column_vals: Vec<Value> = Vec::with_capacity(num_columns);
+    for i in 0..num_columns {
+        match row.get::<usize, InteropValue>(i) {
+            Ok(v) => column_vals.push(v.into()),
+            Err(e) => log::warn!("Couldn't convert row column to value: {}", e),
         }
     }
+    column_vals
+}
 
-    if !disable_foreign_keys {
-        log::info!("Enabling foreign key constraints");
-        if let Err(e) = db_conn.execute_batch("PRAGMA foreign_keys = ON") {
-            log::error!("Couldn't enable foreign key constraints: {}", e);
-            std::process::exit(1);
+/// Runs a prepared statement and maps every row into the shape `format`
+/// asks for: positional arrays, or objects keyed by column name.
+fn query_rows(stmt: &mut Statement, args: &[Value], format: OutputFormat) -> rusqlite::Result<Rows> {
+    let bound = bind_args(args);
+    match format {
+        OutputFormat::Rows => {
+            let rows = stmt.query_map(params_from_iter(&bound), |row| {
+                Ok(row_values(row, row.as_ref().column_count()))
+            })?;
+
+            let mut result_rows = Vec::new();
+            for queried_row in rows {
+                match queried_row {
+                    Ok(row) => result_rows.push(row),
+                    Err(e) => log::error!("Queried row had an error: {}", e),
+                }
+            }
+            Ok(Rows::Positional(result_rows))
         }
-    }
+        OutputFormat::Objects => {
+            let column_names: Vec<String> =
+                stmt.column_names().into_iter().map(String::from).collect();
 
-    if let Some(extensions_to_load) = load_extension {
-        for ext_to_load in extensions_to_load {
-            log::info!("Loading extension from path {}", &ext_to_load);
-            if let Err(e) = unsafe { db_conn.load_extension(&ext_to_load, None) } {
-                log::error!("Couldn't load extension {}: {}", ext_to_load, e);
-                std::process::exit(1);
+            let rows = stmt.query_map(params_from_iter(&bound), |row| {
+                let values = row_values(row, column_names.len());
+                let mut obj = serde_json::Map::with_capacity(values.len());
+                for (name, value) in column_names.iter().zip(values) {
+                    obj.insert(name.clone(), value);
+                }
+                Ok(obj)
+            })?;
+
+            let mut result_rows = Vec::new();
+            for queried_row in rows {
+                match queried_row {
+                    Ok(row) => result_rows.push(row),
+                    Err(e) => log::error!("Queried row had an error: {}", e),
+                }
             }
+            Ok(Rows::Named(result_rows))
         }
     }
+}
 
-    if collect_metadata {
-        log::info!("Enabling metadata collection");
-        if let Err(e) = db_conn.execute_batch("CREATE TABLE IF NOT EXISTS __metadata_query (id INTEGER, payload TEXT NOT NULL, started_at TEXT NOT NULL, finished_at TEXT NOT NULL, PRIMARY KEY(id))") {
-            log::error!("Could not create metadata query table: {}", e);
-            std::process::exit(1);
+/// Whether `sql` is safe to run against a pooled reader connection. Prepares
+/// the statement and asks SQLite directly via `Statement::readonly()` rather
+/// than guessing from the SQL text; anything that fails to prepare here is
+/// treated as not read-only so it falls back to the writer. Uses the
+/// connection's statement cache, so a single read-only statement, which
+/// then runs on the same reader via `prepare_cached`, isn't compiled twice.
+/// Statements routed to the writer are prepared again on the writer
+/// connection, and batch statements are compiled again by `run_batch`.
+fn is_readonly(conn: &Connection, sql: &str) -> bool {
+    match conn.prepare_cached(sql) {
+        Ok(stmt) => stmt.readonly(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `sql` is schema-changing DDL, in which case any cached statement
+/// referencing the same tables/columns could be planned against a schema
+/// that no longer exists.
+fn is_schema_changing(sql: &str) -> bool {
+    let sql = sql.trim_start();
+    ["CREATE", "DROP", "ALTER", "VACUUM", "REINDEX"]
+        .iter()
+        .any(|keyword| sql.get(..keyword.len()).is_some_and(|head| head.eq_ignore_ascii_case(keyword)))
+}
+
+fn ok_reply(output: &Output) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(output), warp::http::StatusCode::OK)
+}
+
+fn error_reply() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&Output::default()),
+        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
+
+/// Records a processed request's payload and timing in `__metadata_query`.
+/// Always goes through the writer connection since it's the only one
+/// performing inserts.
+fn record_metadata(
+    writer: &Mutex<Connection>,
+    input: &Input,
+    started_at: DateTime<Local>,
+    finished_at: DateTime<Local>,
+) {
+    let db = match writer.lock() {
+        Ok(db) => db,
+        Err(e) => {
+            log::warn!("Couldn't acquire lock to DB to store query metadata: {}", e);
+            return;
         }
+    };
+    if let Err(e) = db.execute(
+        "INSERT INTO __metadata_query (payload, started_at, finished_at) VALUES (?, ?, ?)",
+        [
+            &serde_json::to_string(input).unwrap(),
+            &started_at.to_rfc3339(),
+            &finished_at.to_rfc3339(),
+        ],
+    ) {
+        log::warn!("Error occurred while storing query metadata: {}", e);
     }
+}
 
-    let exclusive_db = Arc::new(Mutex::new(db_conn));
-
-    let r = warp::post().and(warp::body::json()).map(move |input| {
-        let Input { sql, args } = &input;
-        log::debug!("Received SQL {:?} with args {:?}", sql, args);
-        let mut is_single_statement = false;
-        let mut is_batch_statement = false;
-        match sql {
-            SqlInput::Single(_) => {
-                log::info!("Single statement");
-                is_single_statement = true;
-            }
-            SqlInput::Batch(_) => {
-                log::info!("Batch statements");
-                is_batch_statement = true;
-            }
+/// Extracts each statement's argument array out of a batch's flat `args`
+/// list, failing if any entry isn't a JSON array.
+fn batch_arg_arrays(args: &[Value]) -> Result<Vec<&Vec<Value>>, ()> {
+    let mut arrays = Vec::with_capacity(args.len());
+    for (idx, value) in args.iter().enumerate() {
+        match value {
+            Value::Array(a) => arrays.push(a),
             _ => {
-                log::error!("Received mismatched statement and argument types. (single / batch or batch / single)");
-                return warp::reply::with_status(
-                    warp::reply::json(&Output::default()),
-                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                )
+                log::error!("Did not find arguments array at index {}", idx);
+                return Err(());
             }
         }
+    }
+    Ok(arrays)
+}
+
+/// Executes each statement in a batch, in order, against `conn`, pairing it
+/// with its same-index argument array.
+fn run_batch(conn: &Connection, sqls: &[String], arg_arrays: &[&Vec<Value>]) -> rusqlite::Result<()> {
+    for (sql_stmt, these_args) in sqls.iter().zip(arg_arrays.iter()) {
+        let bound = bind_args(these_args);
+        conn.execute(sql_stmt, params_from_iter(&bound))?;
+        if is_schema_changing(sql_stmt) {
+            conn.flush_prepared_statement_cache();
+        }
+    }
+    Ok(())
+}
+
+/// Triggers a hot online backup of the database to `input.destination`
+/// without stopping query serving, by backing up from a checked-out reader
+/// connection rather than the writer.
+/// Runs the (synchronous, potentially multi-second) backup on a blocking
+/// thread rather than a tokio worker, since it holds a reader checked out
+/// of the pool and sleeps between steps for as long as the backup takes --
+/// doing that on a worker thread would stall other async work and starve
+/// the reader pool of one of its (by default, few) connections.
+async fn handle_backup(
+    input: BackupInput,
+    reader_pool: Arc<ReaderPool>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    log::info!("Backing up database to {}", &input.destination);
+
+    let result = tokio::task::spawn_blocking(move || {
+        let reader = reader_pool.checkout();
+        backup::backup_to(&reader, &input.destination)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(result)) => warp::reply::with_status(
+            warp::reply::json(&BackupOutput {
+                total_pages: result.total_pages,
+                elapsed_ms: result.elapsed.as_millis(),
+            }),
+            warp::http::StatusCode::OK,
+        ),
+        Ok(Err(e)) => {
+            log::error!("Backup failed: {}", e);
+            warp::reply::with_status(
+                warp::reply::json(&BackupOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+        Err(e) => {
+            log::error!("Backup task panicked: {}", e);
+            warp::reply::with_status(
+                warp::reply::json(&BackupOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Streams a slice of a BLOB cell out via the incremental BLOB API, so
+/// large binary columns don't have to be base64-inflated into a normal
+/// query response. Served off the reader pool since it's read-only.
+fn handle_read_blob(
+    input: &ReadBlobInput,
+    reader_pool: &ReaderPool,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let reader = reader_pool.checkout();
+    match blob::read_blob(&reader, &input.table, &input.column, input.row_id, input.offset, input.length) {
+        Ok(bytes) => warp::reply::with_status(
+            warp::reply::json(&ReadBlobOutput {
+                data: STANDARD.encode(bytes),
+            }),
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => {
+            log::error!("Reading BLOB failed: {}", e);
+            warp::reply::with_status(
+                warp::reply::json(&ReadBlobOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Writes a slice of bytes into an existing BLOB cell via the incremental
+/// BLOB API. Goes through the writer connection since it's a mutation.
+fn handle_write_blob(
+    input: &WriteBlobInput,
+    writer: &Mutex<Connection>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let bytes = match STANDARD.decode(&input.data) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Couldn't decode base64 BLOB data: {}", e);
+            return warp::reply::with_status(
+                warp::reply::json(&WriteBlobOutput::default()),
+                warp::http::StatusCode::BAD_REQUEST,
+            );
+        }
+    };
+
+    let db = match writer.lock() {
+        Ok(db) => db,
+        Err(e) => {
+            log::error!("Couldn't acquire lock to DB: {}", e);
+            return warp::reply::with_status(
+                warp::reply::json(&WriteBlobOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
+
+    match blob::write_blob(&db, &input.table, &input.column, input.row_id, input.offset, &bytes) {
+        Ok(()) => warp::reply::with_status(
+            warp::reply::json(&WriteBlobOutput {
+                bytes_written: bytes.len(),
+            }),
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => {
+            log::error!("Writing BLOB failed: {}", e);
+            warp::reply::with_status(
+                warp::reply::json(&WriteBlobOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// Streams data-change notifications to a subscriber as Server-Sent Events.
+/// Returns a plain 404 instead when `--enable-change-stream` wasn't passed,
+/// since no hook was ever registered to feed `sender` in that case.
+fn handle_changes(sender: &broadcast::Sender<ChangeEvent>, enabled: bool) -> Box<dyn warp::Reply> {
+    if !enabled {
+        return Box::new(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": "change stream disabled" })),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let events = BroadcastStream::new(sender.subscribe()).filter_map(|event| {
+        event
+            .ok()
+            .and_then(|event| warp::sse::Event::default().json_data(event).ok())
+            .map(Ok::<_, Infallible>)
+    });
+    Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
 
-        let db = exclusive_db.lock();
-        if let Err(e) = db {
+/// Applies a previously captured changeset to the writer connection, so
+/// another sqlite-http instance's mutations can be replayed here instead of
+/// re-sending raw SQL.
+fn handle_apply_changeset(
+    input: &ApplyChangesetInput,
+    writer: &Mutex<Connection>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let db = match writer.lock() {
+        Ok(db) => db,
+        Err(e) => {
             log::error!("Couldn't acquire lock to DB: {}", e);
             return warp::reply::with_status(
-                warp::reply::json(&Output::default()),
+                warp::reply::json(&ApplyChangesetOutput::default()),
                 warp::http::StatusCode::INTERNAL_SERVER_ERROR,
             );
         }
-        let db = db.unwrap();
-        let mut started_at = Local::now();
-        let mut finished_at = Local::now();
-
-        if is_single_statement {
-            let sql = match sql{
-                SqlInput::Single(sql_string) => {
-                    sql_string
+    };
+
+    match session::apply_changeset(&db, &input.changeset, input.on_conflict) {
+        Ok(()) => warp::reply::with_status(
+            warp::reply::json(&ApplyChangesetOutput { applied: true }),
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            warp::reply::with_status(
+                warp::reply::json(&ApplyChangesetOutput::default()),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+fn handle_request(
+    input: &Input,
+    writer: &Mutex<Connection>,
+    reader_pool: &ReaderPool,
+    collect_metadata: bool,
+    default_format: OutputFormat,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let Input {
+        sql,
+        args,
+        track_changes: _,
+        format,
+    } = input;
+    log::debug!("Received SQL {:?} with args {:?}", sql, args);
+    let format = format.unwrap_or(default_format);
+
+    match sql {
+        SqlInput::Single(sql_string) => {
+            log::info!("Single statement");
+
+            let reader = reader_pool.checkout();
+            if is_readonly(&reader, sql_string) {
+                log::debug!("Routing read-only statement to reader pool");
+                let started_at = Local::now();
+                let result = reader
+                    .prepare_cached(sql_string)
+                    .and_then(|mut stmt| query_rows(&mut stmt, args, format));
+                let finished_at = Local::now();
+                drop(reader);
+
+                return match result {
+                    Ok(rows) => {
+                        if collect_metadata {
+                            record_metadata(writer, input, started_at, finished_at);
+                        }
+                        ok_reply(&Output { rows, changeset: None })
+                    }
+                    Err(e) => {
+                        log::error!("Query failed: {}", e);
+                        error_reply()
+                    }
+                };
+            }
+            drop(reader);
+
+            log::debug!("Routing statement to writer");
+            let db = match writer.lock() {
+                Ok(db) => db,
+                Err(e) => {
+                    log::error!("Couldn't acquire lock to DB: {}", e);
+                    return error_reply();
                 }
-                _ => unreachable!(),
             };
 
-            let prepared_stmt = db.prepare(&sql);
+            let prepared_stmt = db.prepare_cached(sql_string);
             if let Err(e) = prepared_stmt {
                 log::error!("Couldn't prepare SQL statement: {}", e);
-                return warp::reply::with_status(
-                    warp::reply::json(&Output::default()),
-                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                );
+                return error_reply();
             }
             let mut prepared_stmt = prepared_stmt.unwrap();
 
-            started_at = Local::now();
-            let rows = prepared_stmt.query_map(params_from_iter(args), |row| {
-                let stmt = row.as_ref();
-                let num_columns = stmt.column_count();
-                let mut column_vals: Vec<Value> = Vec::with_capacity(num_columns);
-                for i in 0..num_columns {
-                    let column_val = row.get::<usize, InteropValue>(i);
-                    if let Err(e) = column_val {
-                        log::warn!("Couldn't convert row column to value: {}", e);
-                        continue;
-                    }
-                    let column_val = column_val.unwrap();
-                    column_vals.push(column_val.into());
-                }
-                Ok(column_vals)
+            let started_at = Local::now();
+            let result = session::with_tracked_session(&db, input.track_changes, || {
+                query_rows(&mut prepared_stmt, args, format)
             });
-            if let Err(e) = rows {
-                log::error!("Query failed: {}", e);
-                return warp::reply::with_status(
-                    warp::reply::json(&Output::default()),
-                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                );
+            let finished_at = Local::now();
+            drop(prepared_stmt);
+            if is_schema_changing(sql_string) {
+                db.flush_prepared_statement_cache();
             }
-            finished_at = Local::now();
+            drop(db);
 
-            let rows = rows.unwrap();
-            let mut result_rows = Vec::new();
-            for queried_row in rows {
-                let queried_row = queried_row;
-                if let Err(e) = queried_row {
-                    log::error!("Queried row had an error: {}", e);
-                    continue;
+            match result {
+                Ok((rows, changeset)) => {
+                    if collect_metadata {
+                        record_metadata(writer, input, started_at, finished_at);
+                    }
+                    ok_reply(&Output { rows, changeset })
+                }
+                Err(e) => {
+                    log::error!("Query failed: {}", e);
+                    error_reply()
                 }
-                let queried_row = queried_row.unwrap();
-                result_rows.push(queried_row);
             }
-
-            return warp::reply::with_status(
-                warp::reply::json(&Output { rows: result_rows }),
-                warp::http::StatusCode::OK,
-            );
         }
-        if is_batch_statement {
-            let sqls = match sql {
-                SqlInput::Batch(sql_strings) => {
-                    sql_strings
-                }
-                _ => unreachable!(),
-            };
+        SqlInput::Batch(sql_strings) => {
+            log::info!("Batch statements");
 
-            if sqls.len() != args.len() {
+            if sql_strings.len() != args.len() {
                 log::error!(
                     "Wasn't provided the same number of sql statements and sets of arguments"
                 );
-                return warp::reply::with_status(
-                    warp::reply::json(&Output::default()),
-                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                );
+                return error_reply();
             }
+            let arg_arrays = match batch_arg_arrays(args) {
+                Ok(arrays) => arrays,
+                Err(()) => return error_reply(),
+            };
+
+            let reader = reader_pool.checkout();
+            let all_readonly = sql_strings.iter().all(|sql| is_readonly(&reader, sql));
+
+            if all_readonly {
+                log::debug!("Routing read-only batch to reader pool");
+                let started_at = Local::now();
+                let result = run_batch(&reader, sql_strings, &arg_arrays);
+                let finished_at = Local::now();
+                drop(reader);
+
+                return match result {
+                    Ok(()) => {
+                        if collect_metadata {
+                            record_metadata(writer, input, started_at, finished_at);
+                        }
+                        ok_reply(&Output::default())
+                    }
+                    Err(e) => {
+                        log::error!("Executing statement failed: {}", e);
+                        error_reply()
+                    }
+                };
+            }
+            drop(reader);
+
+            log::debug!("Routing batch to writer");
+            let db = match writer.lock() {
+                Ok(db) => db,
+                Err(e) => {
+                    log::error!("Couldn't acquire lock to DB: {}", e);
+                    return error_reply();
+                }
+            };
 
             // NOTE: We don't need to begin a transaction here, because we have an
             // exclusive lock to the DB via our mutex
-            started_at = Local::now();
-            for (stmt_idx, sql_stmt) in sqls.iter().enumerate() {
-                let these_args = match args.get(stmt_idx).unwrap() {
-                    Value::Array(args) => args,
-                    _ => {
-                        log::error!("Did not find arguments array at index {}", stmt_idx);
-                        return warp::reply::with_status(
-                            warp::reply::json(&Output::default()),
-                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        );
+            let started_at = Local::now();
+            let result = session::with_tracked_session(&db, input.track_changes, || {
+                run_batch(&db, sql_strings, &arg_arrays)
+            });
+            let finished_at = Local::now();
+            drop(db);
+
+            match result {
+                Ok(((), changeset)) => {
+                    if collect_metadata {
+                        record_metadata(writer, input, started_at, finished_at);
                     }
-                };
-                let stmt_result = db.execute(&sql_stmt, params_from_iter(these_args.iter()));
-                if let Err(e) = stmt_result {
+                    ok_reply(&Output {
+                        rows: Rows::default(),
+                        changeset,
+                    })
+                }
+                Err(e) => {
                     log::error!("Executing statement failed: {}", e);
-                    return warp::reply::with_status(
-                        warp::reply::json(&Output::default()),
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    );
+                    error_reply()
                 }
             }
-            finished_at = Local::now();
         }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let args = Args::parse();
+    log::debug!("Parsed CLI flags: {:?}", &args);
+
+    let Args {
+        db_path,
+        host,
+        collect_metadata,
+        disable_wal_mode,
+        disable_foreign_keys,
+        load_extension,
+        max_readers,
+        default_format,
+        key,
+        cipher_compatibility,
+        enable_change_stream,
+        stmt_cache_size,
+    } = args;
+
+    let connection_config = ConnectionConfig {
+        disable_wal_mode,
+        disable_foreign_keys,
+        load_extension: load_extension.unwrap_or_default(),
+        key,
+        cipher_compatibility,
+        stmt_cache_size,
+    };
+
+    log::info!("Opening writer connection");
+    let writer_conn = match open_configured_connection(&db_path, &connection_config) {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-        if collect_metadata {
-            if let Err(e) = db.execute(
-                "INSERT INTO __metadata_query (payload, started_at, finished_at) VALUES (?, ?, ?)",
-                [
-                    &serde_json::to_string(&input).unwrap(),
-                    &started_at.to_rfc3339(),
-                    &finished_at.to_rfc3339(),
-                ],
-            ) {
-                log::warn!("Error occurred while storing query metadata: {}", e);
+    if collect_metadata {
+        log::info!("Enabling metadata collection");
+        if let Err(e) = writer_conn.execute_batch("CREATE TABLE IF NOT EXISTS __metadata_query (id INTEGER, payload TEXT NOT NULL, started_at TEXT NOT NULL, finished_at TEXT NOT NULL, PRIMARY KEY(id))") {
+            log::error!("Could not create metadata query table: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    log::info!("Opening {} reader connections", max_readers);
+    let mut reader_conns = Vec::with_capacity(max_readers);
+    for _ in 0..max_readers {
+        match open_configured_connection(&db_path, &connection_config) {
+            Ok(conn) => reader_conns.push(conn),
+            Err(e) => {
+                log::error!("Couldn't open reader connection: {}", e);
+                std::process::exit(1);
             }
         }
+    }
 
-        return warp::reply::with_status(
-            warp::reply::json(&Output::default()),
-            warp::http::StatusCode::OK,
-        );
+    let (change_sender, _) = broadcast::channel::<ChangeEvent>(CHANGE_STREAM_CAPACITY);
+    if enable_change_stream {
+        log::info!("Enabling change stream");
+        watch_changes(&writer_conn, change_sender.clone());
+    }
+
+    let writer = Arc::new(Mutex::new(writer_conn));
+    let reader_pool = Arc::new(ReaderPool::new(reader_conns));
+
+    let backup_reader_pool = reader_pool.clone();
+    let backup_route = warp::post()
+        .and(warp::path("backup"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .then(move |input: BackupInput| {
+            let backup_reader_pool = backup_reader_pool.clone();
+            async move { handle_backup(input, backup_reader_pool).await }
+        });
+
+    let apply_changeset_writer = writer.clone();
+    let apply_changeset_route = warp::post()
+        .and(warp::path("apply-changeset"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(move |input: ApplyChangesetInput| {
+            handle_apply_changeset(&input, &apply_changeset_writer)
+        });
+
+    let read_blob_reader_pool = reader_pool.clone();
+    let read_blob_route = warp::post()
+        .and(warp::path("blob"))
+        .and(warp::path("read"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(move |input: ReadBlobInput| handle_read_blob(&input, &read_blob_reader_pool));
+
+    let write_blob_writer = writer.clone();
+    let write_blob_route = warp::post()
+        .and(warp::path("blob"))
+        .and(warp::path("write"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .map(move |input: WriteBlobInput| handle_write_blob(&input, &write_blob_writer));
+
+    let sql_route = warp::post().and(warp::body::json()).map(move |input: Input| {
+        handle_request(&input, &writer, &reader_pool, collect_metadata, default_format)
     });
 
+    let changes_route = warp::get()
+        .and(warp::path("changes"))
+        .and(warp::path::end())
+        .map(move || handle_changes(&change_sender, enable_change_stream));
+
+    let r = backup_route
+        .or(apply_changeset_route)
+        .or(read_blob_route)
+        .or(write_blob_route)
+        .or(changes_route)
+        .or(sql_route);
+
     let host = host.parse();
     if let Err(e) = host {
         log::error!("Could not parse host: {}", e);
@@ -0,0 +1,147 @@
+use rusqlite::Connection;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+/// Pragmas and extensions that every connection in the pool -- the writer
+/// and each reader -- must apply identically so they all observe the same
+/// schema and behavior against the shared WAL-mode file.
+pub struct ConnectionConfig {
+    pub disable_wal_mode: bool,
+    pub disable_foreign_keys: bool,
+    pub load_extension: Vec<String>,
+    /// SQLCipher passphrase. When set, applied via `PRAGMA key` before any
+    /// other PRAGMA or query touches the connection.
+    pub key: Option<String>,
+    /// SQLCipher `cipher_compatibility` PRAGMA value, for opening databases
+    /// encrypted by an older SQLCipher major version.
+    pub cipher_compatibility: Option<u32>,
+    /// Number of prepared statements each connection's LRU cache keeps
+    /// around, keyed by SQL text, so repeating the same query doesn't pay
+    /// to re-parse and re-plan it every time.
+    pub stmt_cache_size: usize,
+}
+
+/// Opens a connection to `db_path` and applies the shared startup PRAGMAs
+/// and extensions. Used for the writer and for every reader so they all
+/// agree on encoding, WAL mode, foreign key enforcement, and loaded
+/// extensions.
+pub fn open_configured_connection(
+    db_path: &str,
+    config: &ConnectionConfig,
+) -> Result<Connection, String> {
+    let conn = Connection::open(db_path).map_err(|e| format!("Couldn't open DB connection: {}", e))?;
+
+    if let Some(key) = &config.key {
+        conn.execute_batch(&format!("PRAGMA key = '{}'", key.replace('\'', "''")))
+            .map_err(|e| format!("Couldn't set encryption key: {}", e))?;
+
+        if let Some(compatibility) = config.cipher_compatibility {
+            conn.execute_batch(&format!("PRAGMA cipher_compatibility = {}", compatibility))
+                .map_err(|e| format!("Couldn't set cipher_compatibility: {}", e))?;
+        }
+
+        // PRAGMA key only primes the cipher context -- SQLCipher doesn't
+        // actually try to decrypt anything until the first real read. Force
+        // that read now so a wrong key fails here instead of on whatever
+        // request happens to run first.
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|e| format!("Couldn't verify encryption key: {}", e))?;
+    }
+
+    conn.execute_batch("PRAGMA encoding = \"UTF-8\"")
+        .map_err(|e| format!("Couldn't set encoding to UTF-8: {}", e))?;
+
+    if !config.disable_wal_mode {
+        conn.execute_batch("PRAGMA journal_mode=WAL")
+            .map_err(|e| format!("Couldn't enable WAL mode: {}", e))?;
+    }
+
+    if !config.disable_foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON")
+            .map_err(|e| format!("Couldn't enable foreign key constraints: {}", e))?;
+    }
+
+    for ext_to_load in &config.load_extension {
+        unsafe { conn.load_extension(ext_to_load, None) }
+            .map_err(|e| format!("Couldn't load extension {}: {}", ext_to_load, e))?;
+    }
+    if !config.load_extension.is_empty() {
+        // Loading an extension can change what a given SQL string compiles
+        // to (e.g. a newly-registered function or virtual table), so don't
+        // let a cached statement from before the load linger.
+        conn.flush_prepared_statement_cache();
+    }
+
+    conn.set_prepared_statement_cache_capacity(config.stmt_cache_size);
+
+    Ok(conn)
+}
+
+/// A fixed-size pool of read-only-workload connections, all pointed at the
+/// same WAL-mode database file as the writer. Connections are checked out
+/// via a channel: `checkout` blocks until one is available, and returning
+/// the `PooledConnection` (via `Drop`) sends it back for reuse.
+pub struct ReaderPool {
+    sender: Sender<Connection>,
+    receiver: Mutex<Receiver<Connection>>,
+}
+
+impl ReaderPool {
+    pub fn new(readers: Vec<Connection>) -> Self {
+        let (sender, receiver) = channel();
+        for conn in readers {
+            sender.send(conn).expect("receiver kept alive by ReaderPool");
+        }
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    /// Checks out a reader connection, blocking until one is returned to
+    /// the pool if all are currently in use.
+    pub fn checkout(&self) -> PooledConnection<'_> {
+        let conn = self
+            .receiver
+            .lock()
+            .expect("reader pool receiver lock poisoned")
+            .recv()
+            .expect("sender kept alive by ReaderPool");
+        PooledConnection {
+            conn: Some(conn),
+            pool: self,
+        }
+    }
+}
+
+/// A reader connection on loan from a [`ReaderPool`]. Returned to the pool
+/// automatically when dropped.
+pub struct PooledConnection<'a> {
+    conn: Option<Connection>,
+    pool: &'a ReaderPool,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection taken before drop")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection taken before drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // If the pool has already been torn down there's nowhere to
+            // return the connection; just let it close.
+            let _ = self.pool.sender.send(conn);
+        }
+    }
+}
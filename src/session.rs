@@ -0,0 +1,66 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rusqlite::session::{ConflictAction, ConflictType, Session};
+use rusqlite::Connection;
+use serde::Deserialize;
+
+/// Mirrors SQLite's changeset conflict-resolution actions so API callers
+/// can choose how `apply_changeset` should react to a conflicting row.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    Omit,
+    Replace,
+    Abort,
+}
+
+impl Default for OnConflict {
+    fn default() -> Self {
+        Self::Abort
+    }
+}
+
+impl From<OnConflict> for ConflictAction {
+    fn from(value: OnConflict) -> Self {
+        match value {
+            OnConflict::Omit => ConflictAction::SQLITE_CHANGESET_OMIT,
+            OnConflict::Replace => ConflictAction::SQLITE_CHANGESET_REPLACE,
+            OnConflict::Abort => ConflictAction::SQLITE_CHANGESET_ABORT,
+        }
+    }
+}
+
+/// Runs `body` against `conn`, and when `track` is set, wraps it in a
+/// session that tracks every table so the resulting changeset can be
+/// returned alongside the normal query output for downstream replication.
+pub fn with_tracked_session<T>(
+    conn: &Connection,
+    track: bool,
+    body: impl FnOnce() -> rusqlite::Result<T>,
+) -> rusqlite::Result<(T, Option<String>)> {
+    if !track {
+        return body().map(|value| (value, None));
+    }
+
+    let mut session = Session::new(conn)?;
+    session.attach(None)?;
+    let value = body()?;
+
+    let mut changeset = Vec::new();
+    session.changeset_strm(&mut changeset)?;
+    Ok((value, Some(STANDARD.encode(changeset))))
+}
+
+/// Decodes `changeset_b64` and applies it to `conn`, resolving conflicts
+/// with `on_conflict`.
+pub fn apply_changeset(conn: &Connection, changeset_b64: &str, on_conflict: OnConflict) -> Result<(), String> {
+    let bytes = STANDARD
+        .decode(changeset_b64)
+        .map_err(|e| format!("Couldn't decode base64 changeset: {}", e))?;
+    let mut input = bytes.as_slice();
+    conn.apply_strm(
+        &mut input,
+        None::<fn(&str) -> bool>,
+        move |_conflict_type: ConflictType, _item| on_conflict.into(),
+    )
+    .map_err(|e| format!("Couldn't apply changeset: {}", e))
+}